@@ -1,6 +1,12 @@
 #[macro_use] extern crate clap;
+extern crate glob;
 extern crate ini;
+extern crate rayon;
+#[macro_use] extern crate serde_json;
 extern crate sha1;
+extern crate tar;
+extern crate tiny_http;
+extern crate xz2;
 
 use std::ffi::{OsStr, OsString};
 use std::fmt;
@@ -11,6 +17,7 @@ use std::path::{Path, PathBuf};
 
 use ini::Ini;
 use ini::ini::Error as IniError;
+use rayon::prelude::*;
 
 
 type Sha1DigestBytes = [u8; 20];
@@ -21,17 +28,30 @@ type MediaSet = Vec<Asset>;
 struct Asset {
 	path: PathBuf,
 	hash: Sha1DigestBytes,
+	mod_name: String,
+	rel_path: PathBuf,
 }
 
 impl Asset {
-	pub fn new(pb: PathBuf, h: Sha1DigestBytes) -> Self {
+	pub fn new(pb: PathBuf, h: Sha1DigestBytes, mod_name: String, rel_path: PathBuf) -> Self {
 		Asset {
 			path: pb,
 			hash: h,
+			mod_name,
+			rel_path,
 		}
 	}
 }
 
+
+// A file discovered during the directory walk, carrying the provenance needed
+// to build both the `Asset` and the manifest once it has been hashed.
+struct Candidate {
+	path: PathBuf,
+	mod_name: String,
+	rel_path: PathBuf,
+}
+
 impl PartialEq for Asset {
 	fn eq(&self, other: &Self) -> bool {
 		self.hash == other.hash
@@ -39,33 +59,94 @@ impl PartialEq for Asset {
 }
 
 
+#[derive(Debug)]
 enum Error {
-	Io(io::Error),
+	Hash { path: PathBuf, source: io::Error },
+	ReadDir { path: PathBuf, source: io::Error },
+	CopyAsset { src: PathBuf, dst: PathBuf, source: io::Error },
+	Write { path: PathBuf, source: io::Error },
+	Metadata { path: PathBuf, source: io::Error },
+	Serve { addr: String, source: io::Error },
 	Ini(IniError),
 }
 
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
-			Error::Io(ref e)  => write!(f, "IO error: {}", e),
+			Error::Hash { ref path, ref source } =>
+				write!(f, "Failed to hash {}: {}", path.display(), source),
+			Error::ReadDir { ref path, ref source } =>
+				write!(f, "Failed to read directory {}: {}", path.display(), source),
+			Error::CopyAsset { ref src, ref dst, ref source } =>
+				write!(f, "Failed to copy {} to {}: {}", src.display(), dst.display(), source),
+			Error::Write { ref path, ref source } =>
+				write!(f, "Failed to write {}: {}", path.display(), source),
+			Error::Metadata { ref path, ref source } =>
+				write!(f, "Failed to read metadata of {}: {}", path.display(), source),
+			Error::Serve { ref addr, ref source } =>
+				write!(f, "Failed to serve on {}: {}", addr, source),
 			Error::Ini(ref e) => write!(f, "Settings file error: {}", e),
 		}
 	}
 }
 
-impl From<io::Error> for Error {
-	fn from(e: io::Error) -> Self { Error::Io(e) }
-}
-
 impl From<IniError> for Error {
 	fn from(e: IniError) -> Self { Error::Ini(e) }
 }
 
 
+#[derive(Clone, Copy)]
+enum MatchType {
+	Include,
+	Exclude,
+}
+
+impl MatchType {
+	fn flip(self) -> Self {
+		match self {
+			MatchType::Include => MatchType::Exclude,
+			MatchType::Exclude => MatchType::Include,
+		}
+	}
+}
+
+
+// An ordered list of glob rules evaluated against each media file's path
+// relative to its mod directory.  Later rules override earlier ones and the
+// default is to include everything not explicitly excluded.  A rule whose
+// pattern contains no `/` matches the file name in any subdirectory, as
+// gitignore patterns do.
+struct Matcher {
+	rules: Vec<(glob::Pattern, MatchType)>,
+}
+
+impl Matcher {
+	fn is_included(&self, rel: &Path) -> bool {
+		let mut included = true;
+		for &(ref pat, kind) in &self.rules {
+			let hit = if pat.as_str().contains('/') {
+				pat.matches_path(rel)
+			} else {
+				rel.file_name()
+					.is_some_and(|n| pat.matches(&n.to_string_lossy()))
+			};
+			if hit {
+				included = match kind {
+					MatchType::Include => true,
+					MatchType::Exclude => false,
+				};
+			}
+		}
+		included
+	}
+}
+
+
 enum AssetCopyMode {
 	Symlink,
 	Hardlink,
 	Copy,
+	Tar,
 	None,
 }
 
@@ -75,16 +156,25 @@ fn to_hex(input: &[u8]) -> String {
 }
 
 
+fn from_hex(s: &str) -> Option<Sha1DigestBytes> {
+	if s.len() != 40 {
+		return None;
+	}
+	let mut out = [0u8; 20];
+	for (i, byte) in out.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+	}
+	Some(out)
+}
+
+
 fn make_absolute(path: &Path) -> PathBuf {
 	if path.is_absolute() {
 		path.to_path_buf()
 	} else {
 		std::env::current_dir()
-			.and_then(|cd| Ok(cd.join(path)))
-			.or_else(|_err| -> io::Result<_> {
-				Ok(path.to_path_buf())
-			})
-			.unwrap()
+			.map(|cd| cd.join(path))
+			.unwrap_or_else(|_err| path.to_path_buf())
 	}
 }
 
@@ -92,7 +182,7 @@ fn make_absolute(path: &Path) -> PathBuf {
 fn hash_file(path: &Path) -> io::Result<Sha1DigestBytes> {
 	let mut buf = [0u8; 8192];
 	let mut hash = sha1::Sha1::new();
-	let mut file = File::open(&path)?;
+	let mut file = File::open(path)?;
 	loop {
 		match file.read(&mut buf) {
 			Ok(0) => break,
@@ -104,37 +194,49 @@ fn hash_file(path: &Path) -> io::Result<Sha1DigestBytes> {
 }
 
 
-fn search_media_dir(ms: &mut MediaSet, path: &Path) -> io::Result<()> {
-	for entry in path.read_dir()? {
-		let pb = entry?.path();
+fn search_media_dir(paths: &mut Vec<Candidate>, path: &Path, base: &Path, matcher: &Matcher) -> Result<(), Error> {
+	let mod_name = base.file_name()
+		.map(|n| n.to_string_lossy().into_owned())
+		.unwrap_or_default();
+	let read_err = |e| Error::ReadDir { path: path.to_path_buf(), source: e };
+	for entry in path.read_dir().map_err(&read_err)? {
+		let pb = entry.map_err(&read_err)?.path();
 		if pb.is_file() {
-			let h = hash_file(pb.as_path())?;
-			ms.push(Asset::new(pb, h));
+			let rel = pb.strip_prefix(base).unwrap_or(pb.as_path());
+			if matcher.is_included(rel) {
+				let rel_path = rel.to_path_buf();
+				paths.push(Candidate {
+					path: pb,
+					mod_name: mod_name.clone(),
+					rel_path,
+				});
+			}
 		}
 	}
 	Ok(())
 }
 
 
-fn search_mod_dir(ms: &mut MediaSet, path: &Path) -> io::Result<()> {
-	static MEDIA_DIRS: &'static [&'static str] = &["textures", "models", "sounds"];
+fn search_mod_dir(paths: &mut Vec<Candidate>, path: &Path, matcher: &Matcher) -> Result<(), Error> {
+	static MEDIA_DIRS: &[&str] = &["textures", "models", "sounds"];
 	for media_dir in MEDIA_DIRS {
 		let media_pb = path.join(media_dir);
 		if media_pb.is_dir() {
-			search_media_dir(ms, media_pb.as_path())?;
+			search_media_dir(paths, media_pb.as_path(), path, matcher)?;
 		}
 	}
 	Ok(())
 }
 
 
-fn search_modpack_dir(ms: &mut MediaSet, path: &Path, mods: Option<&ModList>) -> io::Result<()> {
-	for entry in path.read_dir()? {
-		let entry_path = entry?.path();
+fn search_modpack_dir(paths: &mut Vec<Candidate>, path: &Path, mods: Option<&ModList>, matcher: &Matcher) -> Result<(), Error> {
+	let read_err = |e| Error::ReadDir { path: path.to_path_buf(), source: e };
+	for entry in path.read_dir().map_err(&read_err)? {
+		let entry_path = entry.map_err(&read_err)?.path();
 		if !entry_path.is_dir() {
 			continue;
 		} else if entry_path.join("modpack.txt").exists() {
-			search_modpack_dir(ms, entry_path.as_path(), mods)?;
+			search_modpack_dir(paths, entry_path.as_path(), mods, matcher)?;
 		} else if entry_path.join("init.lua").exists() {
 			if let Some(mod_list) = mods {
 				let mod_name = &entry_path.file_name()
@@ -146,7 +248,7 @@ fn search_modpack_dir(ms: &mut MediaSet, path: &Path, mods: Option<&ModList>) ->
 					continue;
 				}
 			}
-			search_mod_dir(ms, entry_path.as_path())?;
+			search_mod_dir(paths, entry_path.as_path(), matcher)?;
 		}
 		// Otherwise it's probably a VCS directory or something similar
 	}
@@ -154,18 +256,189 @@ fn search_modpack_dir(ms: &mut MediaSet, path: &Path, mods: Option<&ModList>) ->
 }
 
 
-fn write_index(ms: &MediaSet, path: &Path) -> io::Result<()> {
-	let file = File::create(&path)?;
+// Build the include/exclude matcher from the command line, preserving the order
+// in which `--include`/`--exclude` were given so later rules override earlier
+// ones.  A `!`-prefix flips an entry, re-including an otherwise excluded path.
+fn build_matcher(args: &clap::ArgMatches) -> Matcher {
+	let mut entries: Vec<(usize, MatchType, String)> = vec![];
+	for &(name, kind) in &[("include", MatchType::Include), ("exclude", MatchType::Exclude)] {
+		if let (Some(vals), Some(idxs)) = (args.values_of(name), args.indices_of(name)) {
+			for (val, idx) in vals.zip(idxs) {
+				entries.push((idx, kind, val.to_string()));
+			}
+		}
+	}
+	entries.sort_by_key(|&(idx, _, _)| idx);
+
+	let rules = entries.into_iter().map(|(_, kind, raw)| {
+		let (kind, glob) = match raw.strip_prefix('!') {
+			Some(rest) => (kind.flip(), rest),
+			None => (kind, raw.as_str()),
+		};
+		// Patterns were pre-compiled by the `check_glob` arg validator.
+		(glob::Pattern::new(glob).expect("glob validated at parse time"), kind)
+	}).collect();
+
+	Matcher { rules }
+}
+
+
+// Hash each path in parallel on the rayon thread pool, aborting on the first
+// I/O error with the offending path attached to the message.
+fn hash_paths(paths: &[PathBuf]) -> Result<Vec<Sha1DigestBytes>, Error> {
+	paths.par_iter()
+		.map(|pb| hash_file(pb.as_path())
+			.map_err(|e| Error::Hash { path: pb.clone(), source: e }))
+		.collect()
+}
+
+
+fn into_asset(c: &Candidate, h: Sha1DigestBytes) -> Asset {
+	Asset::new(c.path.clone(), h, c.mod_name.clone(), c.rel_path.clone())
+}
+
+
+// On Unix, mods frequently share media as hardlinks (e.g. a game plus a
+// mirrored copy), so hash each distinct `(st_dev, st_ino)` only once and reuse
+// the digest for every path pointing at the same inode.
+#[cfg(unix)]
+fn hash_all(cands: &[Candidate]) -> Result<MediaSet, Error> {
+	use std::collections::HashMap;
+	use std::os::unix::fs::MetadataExt;
+
+	let mut unique: Vec<PathBuf> = vec![];
+	let mut index_of: Vec<usize> = Vec::with_capacity(cands.len());
+	let mut seen: HashMap<(u64, u64), usize> = HashMap::new();
+	for c in cands {
+		let idx = match fs::metadata(&c.path).map(|m| (m.dev(), m.ino())) {
+			Ok(key) => *seen.entry(key).or_insert_with(|| {
+				unique.push(c.path.clone());
+				unique.len() - 1
+			}),
+			// Couldn't stat it; hash it on its own and let hash_file report.
+			Err(_) => {
+				unique.push(c.path.clone());
+				unique.len() - 1
+			}
+		};
+		index_of.push(idx);
+	}
+
+	let digests = hash_paths(&unique)?;
+	Ok(cands.iter().zip(index_of)
+		.map(|(c, idx)| into_asset(c, digests[idx]))
+		.collect())
+}
+
+
+#[cfg(not(unix))]
+fn hash_all(cands: &[Candidate]) -> Result<MediaSet, Error> {
+	let paths: Vec<PathBuf> = cands.iter().map(|c| c.path.clone()).collect();
+	let digests = hash_paths(&paths)?;
+	Ok(cands.iter().zip(digests)
+		.map(|(c, h)| into_asset(c, h))
+		.collect())
+}
+
+
+fn index_bytes(ms: &MediaSet) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(6 + ms.len() * 20);
+	buf.extend_from_slice(b"MTHS\x00\x01");
+	for asset in ms {
+		buf.extend_from_slice(&asset.hash);
+	}
+	buf
+}
+
+
+fn write_index(ms: &MediaSet, path: &Path) -> Result<(), Error> {
+	let write_err = |e| Error::Write { path: path.to_path_buf(), source: e };
+	let file = File::create(path).map_err(&write_err)?;
 	let mut writer = BufWriter::new(file);
-	writer.write_all(b"MTHS\x00\x01")?;
+	writer.write_all(&index_bytes(ms)).map_err(&write_err)?;
+	Ok(())
+}
+
+
+// Write a stable, sorted JSON document describing each deduplicated asset's
+// provenance (hash, size, original relative path and owning mod) plus top-level
+// metadata, so tooling can audit collisions, track provenance or diff builds.
+fn write_manifest(ms: &MediaSet, path: &Path, world: &Path, game: &Path) -> Result<(), Error> {
+	let mut total_bytes: u64 = 0;
+	let mut assets = Vec::with_capacity(ms.len());
 	for asset in ms {
-		writer.write_all(&asset.hash)?;
+		let size = fs::metadata(&asset.path)
+			.map_err(|e| Error::Metadata { path: asset.path.clone(), source: e })?
+			.len();
+		total_bytes += size;
+		assets.push(json!({
+			"hash": to_hex(&asset.hash),
+			"size": size,
+			"path": asset.rel_path.to_string_lossy(),
+			"mod": asset.mod_name,
+		}));
+	}
+
+	let doc = json!({
+		"world": world.to_string_lossy(),
+		"game": game.to_string_lossy(),
+		"count": ms.len(),
+		"total_bytes": total_bytes,
+		"assets": assets,
+	});
+
+	let write_err = |e| Error::Write { path: path.to_path_buf(), source: e };
+	let file = File::create(path).map_err(&write_err)?;
+	serde_json::to_writer_pretty(BufWriter::new(file), &doc)
+		.map_err(|e| write_err(io::Error::other(e.to_string())))
+}
+
+
+// Serve the collection over HTTP the way Minetest's remote media download
+// expects: `GET /index.mth` returns the generated index and `GET /<hexhash>`
+// streams the matching asset.  Unknown hashes get a 404.
+fn serve_media(ms: &MediaSet, addr: &str) -> Result<(), Error> {
+	use std::collections::HashMap;
+
+	let index = index_bytes(ms);
+	let lookup: HashMap<Sha1DigestBytes, PathBuf> =
+		ms.iter().map(|a| (a.hash, a.path.clone())).collect();
+
+	let server = tiny_http::Server::http(addr)
+		.map_err(|e| Error::Serve {
+			addr: addr.to_string(),
+			source: io::Error::other(e.to_string()),
+		})?;
+
+	for request in server.incoming_requests() {
+		let name = request.url().trim_start_matches('/').to_string();
+		if name == "index.mth" {
+			let _ = request.respond(tiny_http::Response::from_data(index.clone()));
+			continue;
+		}
+
+		match from_hex(&name).and_then(|h| lookup.get(&h)) {
+			Some(path) => match File::open(path) {
+				Ok(file) => {
+					let len = file.metadata().ok().map(|m| m.len() as usize);
+					let response = tiny_http::Response::new(
+						tiny_http::StatusCode(200), vec![], file, len, None);
+					let _ = request.respond(response);
+				}
+				Err(_) => {
+					let _ = request.respond(tiny_http::Response::empty(404));
+				}
+			},
+			None => {
+				let _ = request.respond(tiny_http::Response::empty(404));
+			}
+		}
 	}
 	Ok(())
 }
 
 
-fn copy_assets(ms: &MediaSet, path: &Path, mode: AssetCopyMode) -> io::Result<()> {
+fn copy_assets(ms: &MediaSet, path: &Path, mode: AssetCopyMode) -> Result<(), Error> {
 	fn copy_no_result<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<()> {
 		fs::copy(src, dst).map(|_| ())
 	}
@@ -190,19 +463,55 @@ fn copy_assets(ms: &MediaSet, path: &Path, mode: AssetCopyMode) -> io::Result<()
 		AssetCopyMode::Symlink => symlink_file,
 		AssetCopyMode::Hardlink => fs::hard_link,
 		AssetCopyMode::Copy => copy_no_result,
+		AssetCopyMode::Tar => return write_tar(ms, path),
 		AssetCopyMode::None => return Ok(()),
 	};
 
 	for asset in ms {
 		let to_path = path.join(to_hex(&asset.hash));
 		if !to_path.exists() {
-			copy_func(&asset.path, to_path)?;
+			let dst = to_path.clone();
+			copy_func(&asset.path, to_path).map_err(|e| Error::CopyAsset {
+				src: asset.path.clone(),
+				dst,
+				source: e,
+			})?;
 		}
 	}
 	Ok(())
 }
 
 
+fn append_assets<W: Write>(builder: &mut tar::Builder<W>, ms: &MediaSet) -> io::Result<()> {
+	for asset in ms {
+		builder.append_path_with_name(&asset.path, to_hex(&asset.hash))?;
+	}
+	Ok(())
+}
+
+
+// Stream the deduplicated media into a single tar archive whose entry names are
+// the hex hashes, matching the `index.mth` ordering.  A `.xz` suffix wraps the
+// archive in an xz stream with a large dictionary so it is small enough to
+// publish for download.
+fn write_tar(ms: &MediaSet, path: &Path) -> Result<(), Error> {
+	let write_err = |e| Error::Write { path: path.to_path_buf(), source: e };
+	let file = File::create(path).map_err(&write_err)?;
+	let writer = BufWriter::new(file);
+	let compressed = path.extension() == Some(OsStr::new("xz"));
+	if compressed {
+		let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(writer, 9));
+		append_assets(&mut builder, ms).map_err(&write_err)?;
+		builder.into_inner().map_err(&write_err)?.finish().map_err(&write_err)?;
+	} else {
+		let mut builder = tar::Builder::new(writer);
+		append_assets(&mut builder, ms).map_err(&write_err)?;
+		builder.finish().map_err(&write_err)?;
+	}
+	Ok(())
+}
+
+
 fn get_mod_list(path: &Path) -> Result<ModList, IniError> {
 	let world_mt = Ini::load_from_file(path.join("world.mt"))?;
 	let main_sec = world_mt.general_section();
@@ -248,6 +557,20 @@ fn get_args<'a>() -> clap::ArgMatches<'a> {
 		}
 	}
 
+	fn check_positive_int(s: String) -> Result<(), String> {
+		match s.parse::<usize>() {
+			Ok(n) if n > 0 => Ok(()),
+			_ => Err("Job count must be a positive integer.".into()),
+		}
+	}
+
+	fn check_glob(s: String) -> Result<(), String> {
+		let pattern = s.strip_prefix('!').unwrap_or(s.as_str());
+		glob::Pattern::new(pattern)
+			.map(|_| ())
+			.map_err(|e| format!("Invalid glob pattern: {}", e))
+	}
+
 	fn check_new_file(s: &OsStr) -> Result<(), OsString> {
 		let p = make_absolute(Path::new(&s));
 		if p.is_file() || check_parent_dir(&p) {
@@ -265,17 +588,31 @@ fn get_args<'a>() -> clap::ArgMatches<'a> {
 		(@arg world: -w --world <PATH> validator_os(check_existing_dir) "Path to the world directory.")
 		(@arg game:  -g --game  <PATH> validator_os(check_existing_dir) "Path to the game directory.")
 
+		(@arg jobs: -j --jobs [N] validator(check_positive_int)
+			"Maximum number of threads to use for hashing.")
+
+		(@arg exclude: -e --exclude [GLOB] ... number_of_values(1) validator(check_glob)
+			"Exclude media files matching GLOB (repeatable, !-prefix re-includes).")
+		(@arg include: -I --include [GLOB] ... number_of_values(1) validator(check_glob)
+			"Re-include media files matching GLOB (repeatable).")
+
 		(@group output =>
 			(@attributes +multiple +required)
 			(@arg out: -o --out [PATH] validator_os(check_new_dir) display_order(1001)
-				conflicts_with_all(&["media", "index"])
 				"Directory to output media files and index. \
 				Convenience for --index PATH/index.mth --media PATH.")
 			(@arg media: -m --media [PATH] validator_os(check_new_dir) display_order(1001)
 				requires("media_transfer")
 				"Directory to output media files.")
 			(@arg index: -i --index [PATH] validator_os(check_new_file) display_order(1001)
-				"Path to the index file to output."))
+				"Path to the index file to output.")
+			(@arg tar: -t --tar [PATH] validator_os(check_new_file) display_order(1001)
+				"Output all media into a single tar archive.  \
+				A .xz suffix compresses the archive with xz.")
+			(@arg serve: --serve [ADDR] display_order(1001)
+				"Serve the collection over HTTP from ADDR:PORT instead of writing files.")
+			(@arg manifest: --manifest [PATH] validator_os(check_new_file) display_order(1001)
+				"Write a JSON manifest mapping each hash to its source mod and path."))
 
 		// Group these together with display_order
 		(@arg copy: -c --copy display_order(1000) requires("media_out") "Copy assets to output folder.")
@@ -304,6 +641,28 @@ fn get_args<'a>() -> clap::ArgMatches<'a> {
 			.args(&["copy", "symlink", "hardlink"]))
 		.get_matches();
 
+	// clap 2.33 mishandles `conflicts_with_all` on a member of a
+	// `+multiple +required` group (it rejects even a lone member), so the
+	// mutually exclusive output modes are enforced here instead.
+	let conflict = |msg: &str| -> ! {
+		clap::Error::with_description(msg, clap::ErrorKind::ArgumentConflict).exit()
+	};
+	let present = |name| matches.is_present(name);
+	if present("serve")
+			&& ["out", "media", "index", "tar", "manifest", "copy", "symlink", "hardlink"]
+				.iter().any(|&a| present(a)) {
+		conflict("--serve serves the collection over HTTP and can not be \
+			combined with file output options.");
+	}
+	if present("tar") && (present("out") || present("media")) {
+		conflict("--tar writes a single archive and can not be combined \
+			with --out or --media.");
+	}
+	if present("out") && (present("media") || present("index")) {
+		conflict("--out is shorthand for --index and --media and can not be \
+			combined with them.");
+	}
+
 	matches
 }
 
@@ -317,23 +676,15 @@ fn run(args: clap::ArgMatches) -> Result<(), Error> {
 	let game_opt = args.value_of_os("game").unwrap();
 	let game_path = Path::new(&game_opt);
 
-	let out_path = args.value_of_os("out").map(|s| PathBuf::from(s));
+	let out_path = args.value_of_os("out").map(PathBuf::from);
 
-	let media_path = if let Some(media_opt) = args.value_of_os("media") {
-			Some(PathBuf::from(media_opt))
-		} else if let Some(ref out_path) = out_path {
-			Some(out_path.clone())
-		} else {
-			None
-		};
+	let media_path = args.value_of_os("media")
+		.map(PathBuf::from)
+		.or_else(|| out_path.clone());
 
-	let index_path = if let Some(index_opt) = args.value_of_os("index") {
-			Some(PathBuf::from(index_opt))
-		} else if let Some(ref out_path) = out_path {
-			Some(out_path.join("index.mth"))
-		} else {
-			None
-		};
+	let index_path = args.value_of_os("index")
+		.map(PathBuf::from)
+		.or_else(|| out_path.as_ref().map(|p| p.join("index.mth")));
 
 	let copy_type = if args.is_present("copy") {
 			AssetCopyMode::Copy
@@ -345,40 +696,68 @@ fn run(args: clap::ArgMatches) -> Result<(), Error> {
 			AssetCopyMode::None
 		};
 
-	let mut ms = MediaSet::new();
+	// Cap the hashing thread pool if the user asked for a specific job count.
+	if let Some(jobs) = args.value_of("jobs") {
+		let jobs = jobs.parse::<usize>().unwrap();
+		rayon::ThreadPoolBuilder::new()
+			.num_threads(jobs)
+			.build_global()
+			.expect("Failed to configure thread pool");
+	}
+
+	let matcher = build_matcher(&args);
+
+	let mut paths: Vec<Candidate> = vec![];
 	let mods = get_mod_list(world_path)?;
 
 	// Search world mods.
 	let worldmods_path = world_path.join("worldmods");
 	if worldmods_path.exists() {
-		search_modpack_dir(&mut ms, worldmods_path.as_path(), Some(&mods))?;
+		search_modpack_dir(&mut paths, worldmods_path.as_path(), Some(&mods), &matcher)?;
 	}
 
 	// Search game mods.
 	// Note: Game mods can not currently be disabled.
-	search_modpack_dir(&mut ms, game_path.join("mods").as_path(), None)?;
+	search_modpack_dir(&mut paths, game_path.join("mods").as_path(), None, &matcher)?;
 
 	if let Some(mod_paths) = args.values_of_os("mod_paths") {
 		for mod_path in mod_paths {
-			search_modpack_dir(&mut ms,
+			search_modpack_dir(&mut paths,
 					Path::new(&mod_path),
-					Some(&mods))?;
+					Some(&mods),
+					&matcher)?;
 		}
 	}
 
+	// Hash the collected files in parallel on the rayon thread pool.
+	let mut ms = hash_all(&paths)?;
+
 	// Deduplicate list.  Otherwise linking will fail and the index will
 	// be unnecessarily large.
-	ms.sort_by(|a, b| a.hash.cmp(&b.hash));
+	ms.sort_by_key(|a| a.hash);
 	ms.dedup();
 
+	if let Some(manifest_opt) = args.value_of_os("manifest") {
+		write_manifest(&ms, Path::new(&manifest_opt), world_path, game_path)?;
+	}
+
+	if let Some(addr) = args.value_of("serve") {
+		return serve_media(&ms, addr);
+	}
+
 	if let Some(media_path) = media_path {
 		if !media_path.exists() {
-			fs::create_dir(media_path.as_path())?;
+			fs::create_dir(media_path.as_path())
+				.map_err(|e| Error::Write { path: media_path.clone(), source: e })?;
 		}
 
 		copy_assets(&ms, media_path.as_path(), copy_type)?;
 	}
 
+	if let Some(tar_opt) = args.value_of_os("tar") {
+		copy_assets(&ms, Path::new(&tar_opt), AssetCopyMode::Tar)?;
+	}
+
 	if let Some(index_path) = index_path {
 		write_index(&ms, index_path.as_path())?;
 	}
@@ -389,10 +768,54 @@ fn run(args: clap::ArgMatches) -> Result<(), Error> {
 
 fn main() {
 	match run(get_args()) {
-		Ok(()) => return,
+		Ok(()) => {}
 		Err(e) => {
 			println!("{}", e);
 			std::process::exit(1)
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sha1_of(data: &[u8]) -> Sha1DigestBytes {
+		let mut hash = sha1::Sha1::new();
+		hash.update(data);
+		hash.digest().bytes()
+	}
+
+	#[test]
+	fn tar_round_trip() {
+		let dir = std::env::temp_dir().join("mtmc_tar_round_trip");
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		let mut ms = MediaSet::new();
+		for contents in &[&b"alpha"[..], &b"beta"[..], &b"gamma"[..]] {
+			let h = sha1_of(contents);
+			let src = dir.join(to_hex(&h));
+			File::create(&src).unwrap().write_all(contents).unwrap();
+			ms.push(Asset::new(src, h, "test".to_string(), PathBuf::from(to_hex(&h))));
+		}
+
+		let archive = dir.join("media.tar");
+		write_tar(&ms, archive.as_path()).unwrap();
+
+		let mut reader = tar::Archive::new(File::open(&archive).unwrap());
+		let mut count = 0;
+		for entry in reader.entries().unwrap() {
+			let mut entry = entry.unwrap();
+			let name = entry.path().unwrap().to_string_lossy().into_owned();
+			let mut buf = vec![];
+			entry.read_to_end(&mut buf).unwrap();
+			assert_eq!(to_hex(&sha1_of(&buf)), name);
+			count += 1;
+		}
+		assert_eq!(count, ms.len());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}